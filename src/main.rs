@@ -1,12 +1,13 @@
 // Methods for managing k8s resources
-//use kube::Resource;
-use kube::ResourceExt;
+use kube::{Resource, ResourceExt};
 
 // To handle asynchronous networking
 use tokio::time::Duration;
 
 // Kubernetes OpenAPI "objects"
-use k8s_openapi::api::apps::v1::{Deployment};
+use k8s_openapi::api::apps::v1::{Deployment, DeploymentSpec};
+use k8s_openapi::api::core::v1::{Container, PodSpec, PodTemplateSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
 
 // Wrappers for the kubernetes functionalities
 use kube::{
@@ -19,9 +20,24 @@ use kube::{
     runtime::Controller,
     // Wrappers for the k8s API interaction
     Api,
-    api::{ListParams, Patch, PatchParams}
+    api::{ObjectMeta, Patch, PatchParams}
 };
 
+// CustomResource derive macro and the CRD metadata it generates
+use kube::{CustomResource, CustomResourceExt};
+
+// Kubernetes Events, so `kubectl describe` shows what the operator did in addition to logs.
+use kube::runtime::events::{Event as K8sEvent, EventType, Recorder, Reporter};
+
+// Finalizer helper: runs our apply/cleanup closure and manages the finalizer string on the
+// object for us so deletion is only observed once cleanup has actually happened.
+use kube::runtime::finalizer::{finalizer, Event as FinalizerEvent};
+
+// Structures used to build the CRD's OpenAPI schema
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
 // For managing errors
 use thiserror::Error;
 
@@ -31,9 +47,9 @@ use log::{info};
 // Thread safe atomic reference counters for pointers
 use std::sync::Arc;
 
-
-// The k8s Pod structure
-use k8s_openapi::api::core::v1::Pod;
+// Backoff bookkeeping: one entry per object (namespace/name), guarded for concurrent reconciles.
+use std::collections::HashMap;
+use rand::Rng;
 
 // Kubernetes configuration objects
 //use kube::Config;
@@ -45,13 +61,119 @@ use kube::runtime::watcher::Config;
 //use futures_util::stream::stream::StreamExt;
 use futures_util::StreamExt;
 
+// Periodic tick used to re-reconcile every `Example` on a schedule, independent of any
+// Kubernetes watch event (e.g. to pick up drift against a third-party system).
+use tokio_stream::wrappers::IntervalStream;
+
 use kube::runtime::controller::Error as KubeContError;
 
+/// Our custom resource. `kube::CustomResource` generates the `Example` struct (spec + the usual
+/// object metadata) along with the CRD machinery (`Example::crd()`, `ExampleSpec`, etc).
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    group = "example.operator",
+    version = "v1",
+    kind = "Example",
+    namespaced,
+    shortname = "ex",
+    status = "ExampleStatus"
+)]
+pub struct ExampleSpec {
+    /// How many replicas the managed `Deployment` should run.
+    pub replicas: i32,
+    /// Container image the managed `Deployment` should run.
+    pub image: String,
+    /// Name to give the managed `Deployment` and its pods.
+    pub name: String,
+}
+
+/// Observed state we report back on the `Example`, so `kubectl get`/`describe` shows what the
+/// operator has actually done rather than just what was asked for.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
+pub struct ExampleStatus {
+    /// `metadata.generation` of the spec this status was computed from.
+    pub observed_generation: i64,
+    /// Ready replica count last observed on the managed `Deployment`.
+    pub ready_replicas: i32,
+    /// Human-readable description of the outcome of the last reconcile.
+    pub condition: String,
+}
+
+/// Finalizer we register on every `Example` so Kubernetes waits for us to clean up the owned
+/// `Deployment` before the object is actually removed.
+const EXAMPLE_FINALIZER: &str = "example.operator/cleanup";
+
+/// Starting delay for the per-object exponential backoff used by `on_error`.
+const BACKOFF_BASE: Duration = Duration::from_secs(5);
+/// Upper bound the backoff is clamped to, so a persistently failing object still gets retried.
+const BACKOFF_CAP: Duration = Duration::from_secs(300);
+
+/// How often every `Example` is re-reconciled regardless of Kubernetes watch activity, to catch
+/// drift against whatever external/third-party state the operator is meant to track.
+const EXTERNAL_SYNC_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Namespace an `Example` must have, since the CRD is registered as namespaced. Missing it
+/// would mean malformed metadata rather than anything reconcile logic can recover from.
+fn require_namespace(example: &Example) -> Result<String, ExampleError> {
+    example
+        .namespace()
+        .ok_or(ExampleError::MissingObjectKey { key: "metadata.namespace" })
+}
+
+/// Basic sanity checks on the spec before we try to reconcile it into real resources.
+fn validate_spec(spec: &ExampleSpec) -> Result<(), ExampleError> {
+    if spec.name.is_empty() {
+        return Err(ExampleError::InvalidSpec("`name` must not be empty".to_string()));
+    }
+    if spec.replicas < 0 {
+        return Err(ExampleError::InvalidSpec(format!(
+            "`replicas` must not be negative, got {}",
+            spec.replicas
+        )));
+    }
+    Ok(())
+}
+
+/// Key used to look up an `Example`'s backoff entry. `Example` is namespaced but watched
+/// cluster-wide via `Api::all`, so the bare name isn't unique across namespaces - two
+/// same-named `Example`s in different namespaces would otherwise collide on one entry.
+fn backoff_key(example: &Example) -> String {
+    format!("{}/{}", example.namespace().unwrap_or_default(), example.name_any())
+}
+
+/// Computes `min(base * 2^attempts, cap)` plus a little jitter, so a burst of failing objects
+/// doesn't end up hammering the API server in lockstep.
+fn backoff_delay(attempts: u32) -> Duration {
+    let exp = BACKOFF_BASE.saturating_mul(1 << attempts.min(16));
+    let delay = exp.min(BACKOFF_CAP);
+    let jitter_ms = rand::thread_rng().gen_range(0..500);
+    delay + Duration::from_millis(jitter_ms)
+}
+
 /// ContextData just wraps `kube::client::Client` so it can be added implementations
 /// Context injected with each `reconcile` and `on_error` method invocation.
 struct ContextData {
     /// Kubernetes client to make Kubernetes API requests with. Required for K8S resource management.
     client: Client,
+    /// Number of consecutive failed reconciles per object, keyed by `backoff_key` (namespace +
+    /// name, since `Example` is namespaced but watched cluster-wide). Cleared on a successful
+    /// reconcile. A plain `std::sync::Mutex` is fine here: `on_error` is synchronous and the
+    /// critical section never awaits.
+    backoffs: std::sync::Mutex<HashMap<String, u32>>,
+}
+
+/// Reporter name events published through a `Recorder` are attributed to.
+const REPORTER_NAME: &str = "example.operator";
+
+/// Builds a `Recorder` bound to a single `Example`. `Recorder` is tied to one `ObjectReference`
+/// for its whole lifetime, so (unlike the client) it can't be shared across objects in
+/// `ContextData` — one is built fresh at each publish site instead.
+fn recorder_for(client: &Client, example: &Example) -> Recorder {
+    Recorder::new(
+        client.clone(),
+        Reporter::from(REPORTER_NAME),
+        example.object_ref(&()),
+    )
 }
 
 /// Enum for managing different types of errors, needed because the reconciler run function
@@ -64,22 +186,48 @@ pub enum ExampleError {
         #[from]
         source: kube::Error,
     },
-    // TODO: add more types of errors if needed
+    /// Errors reported by the `finalizer` helper while adding/removing our finalizer or
+    /// running our apply/cleanup closure.
+    #[error("Finalizer Error: {0}")]
+    FinalizerError(#[from] Box<kube::runtime::finalizer::Error<ExampleError>>),
+    /// The object was missing metadata we require to reconcile it (e.g. a namespaced `Example`
+    /// without a namespace, which should be impossible but is cheap to check).
+    #[error("Object is missing the expected `{key}` key")]
+    MissingObjectKey { key: &'static str },
+    /// The `spec` failed basic validation before we attempted to reconcile it.
+    #[error("Invalid Example spec: {0}")]
+    InvalidSpec(String),
+    /// Failures serializing our own data with `serde_json`, e.g. the status patch. The `--crd`
+    /// YAML dump does not go through this variant: `print_crd` reports its own failures.
+    #[error("Serialization Error: {0}")]
+    SerializationError(#[from] serde_json::Error),
 }
 
 #[tokio::main]
 async fn main() -> Result <(), ExampleError> {
+    // `--crd` just dumps the CRD YAML and exits, so it can run without a KUBECONFIG at all
+    // (e.g. in CI, piping straight into `kubectl apply -f -`).
+    if std::env::args().any(|arg| arg == "--crd") {
+        print_crd();
+        return Ok(());
+    }
+
     // Load the client
     let kc: Client = Client::try_default()
         .await
         .expect("Expected a valid KUBECONFIG file");
     println!("Hello, world!");
+
     // Get the API client
-    let api: Api<Pod> = Api::all(kc.clone());
+    let api: Api<Example> = Api::all(kc.clone());
     let context: Arc<ContextData> = Arc::new(ContextData::new(kc.clone()));
 
     // Instance of a controller
     Controller::new(api.clone(), Config::default())
+        .owns(Api::<Deployment>::all(kc.clone()), Config::default())
+        .reconcile_all_on(
+            IntervalStream::new(tokio::time::interval(EXTERNAL_SYNC_INTERVAL)).map(|_| ()),
+        )
         .run(reconcile, on_error, context)
         .for_each(|reconciliation_result| async move {
             match reconciliation_result {
@@ -102,22 +250,201 @@ async fn main() -> Result <(), ExampleError> {
     Ok(())
 }
 
+/// Prints the YAML of the `Example` CRD to stdout, so operators can install it with
+/// `cargo run -- --crd | kubectl apply -f -` style tooling.
+fn print_crd() {
+    match serde_yaml::to_string(&Example::crd()) {
+        Ok(crd_yaml) => println!("{}", crd_yaml),
+        Err(e) => eprintln!("Failed to serialize CRD: {:?}", e),
+    }
+}
+
+/// Builds the `Deployment` that should exist for a given `Example`, owned by it so it gets
+/// garbage collected automatically if the `Example` is ever deleted without a finalizer.
+fn build_deployment(example: &Example) -> Deployment {
+    let name = example.spec.name.clone();
+    let mut labels = BTreeMap::new();
+    labels.insert("app".to_string(), name.clone());
+
+    // Owning the Deployment lets the controller's `.owns(...)` watch map it back to this
+    // `Example`, and lets Kubernetes garbage-collect it if we ever lose track of it.
+    let owner_references = example
+        .controller_owner_ref(&())
+        .map(|owner_ref| vec![owner_ref]);
+
+    Deployment {
+        metadata: ObjectMeta {
+            name: Some(name.clone()),
+            namespace: example.namespace(),
+            owner_references,
+            ..ObjectMeta::default()
+        },
+        spec: Some(DeploymentSpec {
+            replicas: Some(example.spec.replicas),
+            selector: LabelSelector {
+                match_labels: Some(labels.clone()),
+                ..LabelSelector::default()
+            },
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels),
+                    ..ObjectMeta::default()
+                }),
+                spec: Some(PodSpec {
+                    containers: vec![Container {
+                        name: name.clone(),
+                        image: Some(example.spec.image.clone()),
+                        ..Container::default()
+                    }],
+                    ..PodSpec::default()
+                }),
+            },
+            ..DeploymentSpec::default()
+        }),
+        ..Deployment::default()
+    }
+}
+
 /// Check reconciliation data
-async fn reconcile(pod: Arc<Pod>, context: Arc<ContextData>
+async fn reconcile(example: Arc<Example>, context: Arc<ContextData>
 ) -> Result<Action, ExampleError> {
-    info!("Status: {:?}", pod.status);
-    let name = pod.name_any();
+    let name = example.name_any();
     info!("Resource name: {}", name);
+
+    let namespace = require_namespace(&example)?;
+    let api: Api<Example> = Api::namespaced(context.client.clone(), &namespace);
+
+    finalizer(&api, EXAMPLE_FINALIZER, example, |event| async {
+        match event {
+            FinalizerEvent::Apply(example) => apply(example, context).await,
+            FinalizerEvent::Cleanup(example) => cleanup(example, context).await,
+        }
+    })
+    .await
+    .map_err(|e| ExampleError::FinalizerError(Box::new(e)))
+}
+
+/// Ensures the `Deployment` owned by this `Example` exists and matches its spec.
+async fn apply(example: Arc<Example>, context: Arc<ContextData>) -> Result<Action, ExampleError> {
+    validate_spec(&example.spec)?;
+    let namespace = require_namespace(&example)?;
+    let deployments: Api<Deployment> = Api::namespaced(context.client.clone(), &namespace);
+    let deployment = build_deployment(&example);
+
+    let applied = deployments
+        .patch(
+            &example.spec.name,
+            &PatchParams::apply(REPORTER_NAME),
+            &Patch::Apply(&deployment),
+        )
+        .await?;
+
+    let ready_replicas = applied
+        .status
+        .as_ref()
+        .and_then(|status| status.ready_replicas)
+        .unwrap_or(0);
+
+    let examples: Api<Example> = Api::namespaced(context.client.clone(), &namespace);
+    patch_status(
+        &examples,
+        &example,
+        ExampleStatus {
+            observed_generation: example.meta().generation.unwrap_or_default(),
+            ready_replicas,
+            condition: "Applied".to_string(),
+        },
+    )
+    .await?;
+
+    recorder_for(&context.client, &example)
+        .publish(K8sEvent {
+            type_: EventType::Normal,
+            reason: "Applied".into(),
+            note: Some(format!("Applied Deployment {}", example.spec.name)),
+            action: "Apply".into(),
+            secondary: None,
+        })
+        .await?;
+
+    // A reconcile made it through, so forget any backoff we'd built up for this object.
+    context.backoffs.lock().unwrap().remove(&backoff_key(&example));
+
     // Reconcile every 10 seconds
     Ok(Action::requeue(Duration::from_secs(10)))
 }
 
+/// Patches the `status` subresource of an `Example` with the given observed state.
+async fn patch_status(
+    api: &Api<Example>,
+    example: &Example,
+    status: ExampleStatus,
+) -> Result<(), ExampleError> {
+    let patch = serde_json::json!({ "status": serde_json::to_value(status)? });
+    api.patch_status(
+        &example.name_any(),
+        &PatchParams::default(),
+        &Patch::Merge(patch),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Removes the `Deployment` owned by this `Example` before the finalizer is lifted and the
+/// object is allowed to actually be deleted.
+async fn cleanup(example: Arc<Example>, context: Arc<ContextData>) -> Result<Action, ExampleError> {
+    let namespace = require_namespace(&example)?;
+    let deployments: Api<Deployment> = Api::namespaced(context.client.clone(), &namespace);
+
+    match deployments.delete(&example.spec.name, &Default::default()).await {
+        Ok(_) => {},
+        Err(kube::Error::Api(e)) if e.code == 404 => {},
+        Err(e) => return Err(e.into()),
+    }
+
+    recorder_for(&context.client, &example)
+        .publish(K8sEvent {
+            type_: EventType::Normal,
+            reason: "Cleanup".into(),
+            note: Some(format!("Deleted Deployment {}", example.spec.name)),
+            action: "Delete".into(),
+            secondary: None,
+        })
+        .await?;
+
+    context.backoffs.lock().unwrap().remove(&backoff_key(&example));
+
+    Ok(Action::await_change())
+}
+
 /// Acctions taken when reonciliation fails
-fn on_error(pod: Arc<Pod>, error: &ExampleError, _context: Arc<ContextData>
+fn on_error(example: Arc<Example>, error: &ExampleError, context: Arc<ContextData>
 ) -> Action {
     eprintln!("Error: {:?}", error);
     info!("Error: {:?}", error);
-    Action::requeue(Duration::from_secs(5))
+
+    let mut backoffs = context.backoffs.lock().unwrap();
+    let attempts = backoffs.entry(backoff_key(&example)).or_insert(0);
+    let delay = backoff_delay(*attempts);
+    *attempts = attempts.saturating_add(1);
+    drop(backoffs);
+
+    // `on_error` is synchronous (required by `Controller::run`), so hop into a blocking
+    // context to publish the failure as a Kubernetes Event via the async recorder.
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(
+            recorder_for(&context.client, &example).publish(K8sEvent {
+                type_: EventType::Warning,
+                reason: "ReconcileFailed".into(),
+                note: Some(format!("{error}")),
+                action: "Reconcile".into(),
+                secondary: None,
+            }),
+        )
+    })
+    .ok();
+
+    Action::requeue(delay)
 }
 
 /// Context data handler
@@ -128,6 +455,77 @@ impl ContextData {
     /// - `client`: A Kubernetes client to make Kubernetes REST API requests with. Resources
     /// will be created and deleted with this client.
     pub fn new(client: Client) -> Self {
-        ContextData { client }
+        ContextData {
+            client,
+            backoffs: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod backoff_delay_tests {
+    use super::*;
+
+    #[test]
+    fn grows_exponentially_before_the_cap() {
+        // Strip the jitter back out so we can assert on the exponential base.
+        assert_eq!(backoff_delay(0).as_secs(), BACKOFF_BASE.as_secs());
+        assert_eq!(backoff_delay(1).as_secs(), BACKOFF_BASE.as_secs() * 2);
+        assert_eq!(backoff_delay(2).as_secs(), BACKOFF_BASE.as_secs() * 4);
+    }
+
+    #[test]
+    fn clamps_to_the_cap_for_large_attempt_counts() {
+        assert_eq!(backoff_delay(16).as_secs(), BACKOFF_CAP.as_secs());
+        assert_eq!(backoff_delay(1000).as_secs(), BACKOFF_CAP.as_secs());
+    }
+
+    #[test]
+    fn adds_up_to_half_a_second_of_jitter() {
+        for attempts in [0, 5, 16] {
+            let delay = backoff_delay(attempts);
+            let base = BACKOFF_BASE.saturating_mul(1 << attempts.min(16)).min(BACKOFF_CAP);
+            assert!(delay >= base);
+            assert!(delay < base + Duration::from_millis(500));
+        }
+    }
+}
+
+#[cfg(test)]
+mod validate_spec_tests {
+    use super::*;
+
+    fn spec(name: &str, replicas: i32) -> ExampleSpec {
+        ExampleSpec {
+            name: name.to_string(),
+            replicas,
+            image: "example:latest".to_string(),
+        }
+    }
+
+    #[test]
+    fn accepts_a_well_formed_spec() {
+        assert!(validate_spec(&spec("web", 3)).is_ok());
+    }
+
+    #[test]
+    fn rejects_an_empty_name() {
+        assert!(matches!(
+            validate_spec(&spec("", 3)),
+            Err(ExampleError::InvalidSpec(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_negative_replicas() {
+        assert!(matches!(
+            validate_spec(&spec("web", -1)),
+            Err(ExampleError::InvalidSpec(_))
+        ));
+    }
+
+    #[test]
+    fn accepts_zero_replicas() {
+        assert!(validate_spec(&spec("web", 0)).is_ok());
     }
 }